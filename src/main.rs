@@ -1,4 +1,5 @@
-use std::collections::VecDeque;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
 use std::fs::File;
 use std::io::Write;
 use std::sync::mpsc::{channel, Receiver};
@@ -18,17 +19,98 @@ use rand::prelude::*;
 
 type TermCoord = (u16, u16);
 
+// base tick delays, matching the original fixed-speed values; cell size is taller than it is
+// wide in most terminals, hence the different vertical/horizontal rates
+const BASE_VERTICAL_DELAY: u64 = 70;
+const BASE_HORIZONTAL_DELAY: u64 = 50;
+const MIN_DELAY: u64 = 20;
+const DELAY_STEP: u64 = 2;
+
+const DEFAULT_MAX_HISTORY: usize = 1000;
+
+// how many normal pellets stay on the board at once
+const NORMAL_FOOD_COUNT: usize = 2;
+// ticks between chances to spawn a bonus pellet, and how long one lasts before despawning
+const BONUS_SPAWN_INTERVAL: u64 = 100;
+const BONUS_LIFETIME: u32 = 40;
+
+// the intended contents of a single cell, decoupled from when/whether it actually gets drawn
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Cell {
+    Empty,
+    Head,
+    Body,
+    Food,
+    BonusFood,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum FoodKind {
+    Normal,
+    Bonus,
+}
+
+impl FoodKind {
+    fn value(self) -> u32 {
+        match self {
+            FoodKind::Normal => 1,
+            FoodKind::Bonus => 5,
+        }
+    }
+
+    fn cell(self) -> Cell {
+        match self {
+            FoodKind::Normal => Cell::Food,
+            FoodKind::Bonus => Cell::BonusFood,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Food {
+    pos: TermCoord,
+    kind: FoodKind,
+    // ticks left before a bonus pellet despawns; normal pellets never expire
+    ticks_left: Option<u32>,
+}
+
+// a point-in-time copy of everything needed to redraw a past tick
+#[derive(Clone)]
+struct Snapshot {
+    snake: VecDeque<TermCoord>,
+    foods: Vec<Food>,
+    last_key: Key,
+    eaten: usize,
+}
+
 struct Game {
     term: RawTerminal<File>,
     snake: VecDeque<TermCoord>,
     last_key: Key,
-    food: TermCoord,
+    foods: Vec<Food>,
     bounds: TermCoord,
     events: Receiver<Key>,
+    wrap: bool,
+    autopilot: bool,
+    // back buffer for the frame being built by `update`, and the buffer matching what's
+    // currently on screen; `render` diffs the two and only writes the cells that changed
+    buffer: Vec<Vec<Cell>>,
+    rendered: Vec<Vec<Cell>>,
+    // ring buffer of past ticks, bounded at `max_history`; `rewind` is the index currently
+    // being viewed while paused, or None while the game is live
+    history: VecDeque<Snapshot>,
+    max_history: usize,
+    rewind: Option<usize>,
+    // number of food eaten so far; drives the progressive speed-up in `tick_delay`
+    eaten: usize,
+    // sum of the values of all food eaten so far; reported as the final score
+    score: u32,
+    // ticks survived; used to time bonus food spawns
+    ticks: u64,
 }
 
 impl Game {
-    fn new() -> Self {
+    fn new(wrap: bool, autopilot: bool, max_history: usize) -> Self {
         let (tx, rx) = channel();
 
         // have to make a channel and send key events over it so that we don't block the main loop
@@ -48,46 +130,215 @@ impl Game {
             cursor::Hide
         )
         .unwrap();
-        let snake = vec![(bounds.0 / 2, bounds.1 / 2)].into();
+        let snake: VecDeque<TermCoord> = vec![(bounds.0 / 2, bounds.1 / 2)].into();
+        let empty_row = vec![Cell::Empty; bounds.0 as usize];
+        let mut foods = Vec::new();
+        for _ in 0..NORMAL_FOOD_COUNT {
+            let pos = Game::generate_food_pos(bounds, &snake, &foods);
+            foods.push(Food {
+                pos,
+                kind: FoodKind::Normal,
+                ticks_left: None,
+            });
+        }
         let mut game = Game {
             term,
-            food: Game::generate_food_pos(bounds, &snake),
+            foods,
             snake,
             last_key: Key::Right,
             bounds,
             events: rx,
+            wrap,
+            autopilot,
+            buffer: vec![empty_row.clone(); bounds.1 as usize],
+            rendered: vec![empty_row; bounds.1 as usize],
+            history: VecDeque::new(),
+            max_history,
+            rewind: None,
+            eaten: 0,
+            score: 0,
+            ticks: 0,
         };
 
-        // draw initial state
-        game.ink(game.snake[0], &color::Red);
-        game.ink(game.food, &color::Green);
+        game.draw_live();
 
         game
     }
 
-    fn ink(&mut self, pos: TermCoord, color: &dyn color::Color) {
-        write!(
-            self.term,
-            "{}{}{} {}",
-            cursor::Save,
-            cursor::Goto(pos.0, pos.1),
-            color::Bg(color),
-            cursor::Restore
-        )
-        .unwrap()
+    fn ink(&mut self, pos: TermCoord, cell: Cell) {
+        self.buffer[pos.1 as usize - 1][pos.0 as usize - 1] = cell;
     }
 
     fn de_ink(&mut self, pos: TermCoord) {
-        write!(self.term, "{} ", cursor::Goto(pos.0, pos.1)).unwrap()
+        self.ink(pos, Cell::Empty);
+    }
+
+    // paints the actual live snake/food state into the buffer and renders it; used for the
+    // initial frame and to restore the real picture after a rewind leaves stale history on
+    // screen
+    fn draw_live(&mut self) {
+        for row in self.buffer.iter_mut() {
+            row.fill(Cell::Empty);
+        }
+
+        for (i, &pos) in self.snake.clone().iter().enumerate() {
+            let cell = if i == 0 { Cell::Head } else { Cell::Body };
+            self.ink(pos, cell);
+        }
+        for food in self.foods.clone() {
+            self.ink(food.pos, food.kind.cell());
+        }
+
+        self.render();
     }
 
-    fn generate_food_pos(bounds: TermCoord, snake: &VecDeque<TermCoord>) -> TermCoord {
+    // diffs `buffer` against what's already drawn (`rendered`) and writes only the cells that
+    // changed, as a single batched sequence followed by one flush
+    fn render(&mut self) {
+        let mut out = Vec::new();
+        for y in 0..self.buffer.len() {
+            for x in 0..self.buffer[y].len() {
+                if self.buffer[y][x] == self.rendered[y][x] {
+                    continue;
+                }
+
+                let pos = (x as u16 + 1, y as u16 + 1);
+                match self.buffer[y][x] {
+                    Cell::Empty => {
+                        write!(
+                            out,
+                            "{}{} ",
+                            cursor::Goto(pos.0, pos.1),
+                            color::Bg(color::Reset)
+                        )
+                        .unwrap();
+                    }
+                    Cell::Head => {
+                        write!(
+                            out,
+                            "{}{} ",
+                            cursor::Goto(pos.0, pos.1),
+                            color::Bg(color::Red)
+                        )
+                        .unwrap();
+                    }
+                    Cell::Body => {
+                        write!(
+                            out,
+                            "{}{} ",
+                            cursor::Goto(pos.0, pos.1),
+                            color::Bg(color::Blue)
+                        )
+                        .unwrap();
+                    }
+                    Cell::Food => {
+                        write!(
+                            out,
+                            "{}{} ",
+                            cursor::Goto(pos.0, pos.1),
+                            color::Bg(color::Green)
+                        )
+                        .unwrap();
+                    }
+                    Cell::BonusFood => {
+                        write!(
+                            out,
+                            "{}{} ",
+                            cursor::Goto(pos.0, pos.1),
+                            color::Bg(color::Yellow)
+                        )
+                        .unwrap();
+                    }
+                }
+            }
+        }
+
+        self.rendered = self.buffer.clone();
+        self.term.write_all(&out).unwrap();
+        self.term.flush().unwrap();
+    }
+
+    // draws a snapshot's snake/food onto the buffer, overwriting whatever the buffer currently
+    // holds; used for rewind and replay, where the live game state isn't what should be on screen
+    fn draw_snapshot(&mut self, snapshot: &Snapshot) {
+        for row in self.buffer.iter_mut() {
+            row.fill(Cell::Empty);
+        }
+
+        for (i, &pos) in snapshot.snake.iter().enumerate() {
+            let cell = if i == 0 { Cell::Head } else { Cell::Body };
+            self.buffer[pos.1 as usize - 1][pos.0 as usize - 1] = cell;
+        }
+        for food in &snapshot.foods {
+            self.buffer[food.pos.1 as usize - 1][food.pos.0 as usize - 1] = food.kind.cell();
+        }
+
+        self.render();
+    }
+
+    fn record_history(&mut self) {
+        if self.max_history == 0 {
+            return;
+        }
+        if self.history.len() == self.max_history {
+            self.history.pop_front();
+        }
+        self.history.push_back(Snapshot {
+            snake: self.snake.clone(),
+            foods: self.foods.clone(),
+            last_key: self.last_key,
+            eaten: self.eaten,
+        });
+    }
+
+    // advances the rewind cursor (or resumes the live game on 'p') in response to one key press,
+    // then re-renders whichever snapshot is now being viewed
+    fn step_rewind(&mut self) {
+        let index = self.rewind.unwrap();
+
+        match self.events.try_recv() {
+            Ok(Key::Char('p')) => {
+                self.rewind = None;
+                self.draw_live();
+                return;
+            }
+            Ok(Key::Left | Key::Char('h')) => {
+                self.rewind = Some(index.saturating_sub(1));
+            }
+            Ok(Key::Right | Key::Char('l')) => {
+                self.rewind = Some((index + 1).min(self.history.len() - 1));
+            }
+            _ => {}
+        }
+
+        let snapshot = self.history[self.rewind.unwrap()].clone();
+        self.draw_snapshot(&snapshot);
+    }
+
+    // plays the whole recorded history back from the first snapshot at the original tick rate
+    fn replay(&mut self) {
+        for i in 0..self.history.len() {
+            let snapshot = self.history[i].clone();
+            let vertical = matches!(snapshot.last_key, Key::Up | Key::Down);
+            self.draw_snapshot(&snapshot);
+            thread::sleep(Duration::from_millis(Game::tick_delay(
+                vertical,
+                snapshot.eaten,
+            )));
+        }
+    }
+
+    fn generate_food_pos(
+        bounds: TermCoord,
+        snake: &VecDeque<TermCoord>,
+        foods: &[Food],
+    ) -> TermCoord {
         let mut rng = rand::thread_rng();
         let mut food = (
             rng.gen_range(10..bounds.0 - 10),
             rng.gen_range(10..bounds.1 - 10),
         );
-        while snake.contains(&food) {
+        while snake.contains(&food) || foods.iter().any(|f| f.pos == food) {
             food = (
                 rng.gen_range(10..bounds.0 - 10),
                 rng.gen_range(10..bounds.1 - 10),
@@ -156,71 +407,296 @@ impl Game {
         }
     }
 
-    fn update(&mut self) -> Result<(), ()> {
-        let old_head = self.snake.front().unwrap().to_owned();
-        let mut new_head = old_head;
+    // applies `key` to `pos`, wrapping across the edges when `self.wrap` is set; returns None
+    // when the move would cross a lethal edge
+    fn advance(&self, pos: TermCoord, key: Key) -> Option<TermCoord> {
+        let mut next = pos;
 
-        if new_head.0 == 0 || new_head.1 == 0 {
-            return Err(());
-        }
-
-        match self.last_key {
-            Key::Up => new_head.1 = new_head.1.checked_sub(1).ok_or(())?,
-            Key::Down => new_head.1 += 1,
-            Key::Left => new_head.0 = new_head.0.checked_sub(1).ok_or(())?,
-            Key::Right => new_head.0 += 1,
+        match key {
+            Key::Up => {
+                next.1 = match next.1.checked_sub(1) {
+                    Some(y) if y > 0 => y,
+                    _ if self.wrap => self.bounds.1 - 1,
+                    _ => return None,
+                }
+            }
+            Key::Down => {
+                next.1 += 1;
+                if next.1 >= self.bounds.1 {
+                    if self.wrap {
+                        next.1 = 1;
+                    } else {
+                        return None;
+                    }
+                }
+            }
+            Key::Left => {
+                next.0 = match next.0.checked_sub(1) {
+                    Some(x) if x > 0 => x,
+                    _ if self.wrap => self.bounds.0 - 1,
+                    _ => return None,
+                }
+            }
+            Key::Right => {
+                next.0 += 1;
+                if next.0 >= self.bounds.0 {
+                    if self.wrap {
+                        next.0 = 1;
+                    } else {
+                        return None;
+                    }
+                }
+            }
             _ => unreachable!(),
         }
 
+        Some(next)
+    }
+
+    fn update(&mut self) -> Result<(), ()> {
+        let old_head = self.snake.front().unwrap().to_owned();
+        let new_head = self.advance(old_head, self.last_key).ok_or(())?;
+
         if new_head == old_head {
             return Ok(());
         }
 
-        self.ink(old_head, &color::Blue);
+        self.ink(old_head, Cell::Body);
         self.valid_head(new_head)?;
-        self.ink(new_head, &color::Red);
+        self.ink(new_head, Cell::Head);
         self.snake.push_front(new_head);
 
-        if *self.snake.front().unwrap() == self.food {
-            self.food = Game::generate_food_pos(self.bounds, &self.snake);
-            self.ink(self.food, &color::Green);
+        self.ticks += 1;
+        self.update_foods(new_head);
+
+        Ok(())
+    }
+
+    // eats whatever food the new head landed on (or pops the tail if it didn't land on any),
+    // then ages and spawns bonus food for this tick
+    fn update_foods(&mut self, head: TermCoord) {
+        if let Some(index) = self.foods.iter().position(|food| food.pos == head) {
+            let food = self.foods.remove(index);
+            self.eaten += 1;
+            self.score += food.kind.value();
+
+            if food.kind == FoodKind::Normal {
+                let pos = Game::generate_food_pos(self.bounds, &self.snake, &self.foods);
+                self.ink(pos, Cell::Food);
+                self.foods.push(Food {
+                    pos,
+                    kind: FoodKind::Normal,
+                    ticks_left: None,
+                });
+            }
         } else {
             let old_tail = self.snake.pop_back().unwrap();
             self.de_ink(old_tail);
         }
 
-        Ok(())
+        let mut i = 0;
+        while i < self.foods.len() {
+            match self.foods[i].ticks_left {
+                Some(0) => {
+                    let expired = self.foods.remove(i);
+                    self.de_ink(expired.pos);
+                }
+                Some(ref mut ticks_left) => {
+                    *ticks_left -= 1;
+                    i += 1;
+                }
+                None => i += 1,
+            }
+        }
+
+        let no_bonus_active = !self.foods.iter().any(|food| food.kind == FoodKind::Bonus);
+        if self.ticks.is_multiple_of(BONUS_SPAWN_INTERVAL) && no_bonus_active {
+            let pos = Game::generate_food_pos(self.bounds, &self.snake, &self.foods);
+            self.ink(pos, Cell::BonusFood);
+            self.foods.push(Food {
+                pos,
+                kind: FoodKind::Bonus,
+                ticks_left: Some(BONUS_LIFETIME),
+            });
+        }
+    }
+
+    fn manhattan(a: TermCoord, b: TermCoord) -> u32 {
+        (a.0 as i32 - b.0 as i32).unsigned_abs() + (a.1 as i32 - b.1 as i32).unsigned_abs()
+    }
+
+    // the first key of a path from the head to the food nearest by Manhattan distance, found via
+    // grid A* with Manhattan distance as the heuristic; obstacles are the snake body and (outside
+    // wrap mode) the outer bounds, both already encoded in `advance`/`self.snake.contains`.
+    // Outside wrap mode the path is a shortest one; Manhattan distance ignores wrapping, so in
+    // wrap mode neither the chosen food nor the path is always the nearest/shortest
+    fn path_to_food_key(&self) -> Option<Key> {
+        let start = *self.snake.front().unwrap();
+        let goal = self
+            .foods
+            .iter()
+            .map(|food| food.pos)
+            .min_by_key(|&pos| Game::manhattan(start, pos))?;
+        if start == goal {
+            return None;
+        }
+        let opposite = Game::opposite(self.last_key);
+
+        let mut open = BinaryHeap::new();
+        open.push((Reverse(Game::manhattan(start, goal)), start));
+
+        let mut g_score = HashMap::new();
+        g_score.insert(start, 0u32);
+        let mut came_from: HashMap<TermCoord, (TermCoord, Key)> = HashMap::new();
+
+        while let Some((_, current)) = open.pop() {
+            if current == goal {
+                let mut node = current;
+                loop {
+                    let (prev, key) = came_from[&node];
+                    if prev == start {
+                        return Some(key);
+                    }
+                    node = prev;
+                }
+            }
+
+            for key in [Key::Up, Key::Down, Key::Left, Key::Right] {
+                if current == start && key == opposite {
+                    continue;
+                }
+
+                let next = match self.advance(current, key) {
+                    Some(pos) => pos,
+                    None => continue,
+                };
+                if self.snake.contains(&next) {
+                    continue;
+                }
+
+                let tentative_g = g_score[&current] + 1;
+                if tentative_g < *g_score.get(&next).unwrap_or(&u32::MAX) {
+                    g_score.insert(next, tentative_g);
+                    came_from.insert(next, (current, key));
+                    open.push((Reverse(tentative_g + Game::manhattan(next, goal)), next));
+                }
+            }
+        }
+
+        None
+    }
+
+    fn free_neighbor_count(&self, pos: TermCoord) -> usize {
+        [Key::Up, Key::Down, Key::Left, Key::Right]
+            .into_iter()
+            .filter(|&key| {
+                self.advance(pos, key)
+                    .map(|next| !self.snake.contains(&next))
+                    .unwrap_or(false)
+            })
+            .count()
+    }
+
+    // used when the food is walled off by the body: pick whichever legal neighbor leaves the
+    // most free space around it, so the snake stalls instead of driving itself into a dead end
+    fn survival_key(&self) -> Option<Key> {
+        let start = *self.snake.front().unwrap();
+        let opposite = Game::opposite(self.last_key);
+
+        [Key::Up, Key::Down, Key::Left, Key::Right]
+            .into_iter()
+            .filter(|&key| key != opposite)
+            .filter_map(|key| {
+                let next = self.advance(start, key)?;
+                if self.snake.contains(&next) {
+                    None
+                } else {
+                    Some((key, self.free_neighbor_count(next)))
+                }
+            })
+            .max_by_key(|&(_, free)| free)
+            .map(|(key, _)| key)
+    }
+
+    fn autopilot_key(&self) -> Key {
+        self.path_to_food_key()
+            .or_else(|| self.survival_key())
+            .unwrap_or(self.last_key)
     }
 
     fn vertical(&self) -> bool {
         matches!(self.last_key, Key::Up | Key::Down)
     }
 
+    // tick delay shrinks by DELAY_STEP per food eaten, down to MIN_DELAY; both directions
+    // lose the same amount, so the vertical/horizontal gap of the fixed-speed delays holds
+    // until the horizontal delay reaches the floor, then narrows to zero
+    fn tick_delay(vertical: bool, eaten: usize) -> u64 {
+        let base = if vertical {
+            BASE_VERTICAL_DELAY
+        } else {
+            BASE_HORIZONTAL_DELAY
+        };
+
+        base.saturating_sub(eaten as u64 * DELAY_STEP).max(MIN_DELAY)
+    }
+
     fn game_loop(&mut self) -> Result<(), ()> {
         loop {
+            if self.rewind.is_some() {
+                self.step_rewind();
+                thread::sleep(Duration::from_millis(50));
+                continue;
+            }
+
             match self.events.try_recv() {
                 // an Err here indicates that no key is available
                 Err(_) => {}
+                Ok(Key::Char('p')) => {
+                    if !self.history.is_empty() {
+                        self.rewind = Some(self.history.len() - 1);
+                    }
+                    continue;
+                }
                 Ok(key) => {
-                    self.handle_key(key)?;
+                    if !self.autopilot {
+                        self.handle_key(key)?;
+                    }
                 }
             }
 
-            self.update()?;
+            if self.autopilot {
+                self.last_key = self.autopilot_key();
+            }
+
+            let result = self.update();
+            self.render();
+            self.record_history();
+            result?;
 
             // different values for vertical and horizontal motion because most terminals have a
-            // cell size that is taller than it is wide.
-            thread::sleep(Duration::from_millis(if self.vertical() { 70 } else { 50 }));
+            // cell size that is taller than it is wide; both shrink as more food is eaten.
+            thread::sleep(Duration::from_millis(Game::tick_delay(
+                self.vertical(),
+                self.eaten,
+            )));
         }
     }
 }
 
 fn main() {
-    let mut game = Game::new();
+    let wrap = std::env::args().any(|arg| arg == "--wrap");
+    let autopilot = std::env::args().any(|arg| arg == "--autopilot");
+    let max_history = std::env::args()
+        .find_map(|arg| arg.strip_prefix("--history=").map(str::to_string))
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(DEFAULT_MAX_HISTORY);
+    let mut game = Game::new(wrap, autopilot, max_history);
 
     let _ = game.game_loop();
+    game.replay();
 
     write!(game.term, "{}{}", cursor::Show, screen::ToMainScreen).unwrap();
     game.term.suspend_raw_mode().unwrap();
-    println!("Score: {}", game.snake.len());
+    println!("Score: {}", game.score);
 }